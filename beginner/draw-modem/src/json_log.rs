@@ -0,0 +1,43 @@
+//! Optional JSON line-output mode for `ModemUartMessages`.
+//!
+//! With the `json-log` feature enabled and the `JSON_LOG_MODE` strap
+//! pin read high at boot, `data` values (the host-facing readings:
+//! ranges, OTA results, anything carried by `ModemUartMessages`) are
+//! serialized as a single newline-terminated JSON object via
+//! `serde_json_core`, into a fresh `heapless::String<U1024>` (the same
+//! buffer type used for log text elsewhere), then written out over the
+//! UARTE directly. This bypasses `nrf52_bin_logger`'s binary framing for
+//! `data` only, so host tooling can pipe it straight into `serde_json`
+//! or any other standard JSON log processor.
+//!
+//! This only ever replaces `data` framing. `warn`/`error`/`log` calls
+//! against `LOGGER` keep going out as `nrf52_bin_logger`'s binary
+//! frames regardless of this mode, since that logger -- not this module
+//! -- owns them; the wire is a mix of binary and JSON lines, not a
+//! clean JSON stream.
+
+use dwm1001::nrf52832_hal::nrf52832_pac::UARTE0;
+use heapless::{consts::*, String};
+use protocol::ModemUartMessages;
+
+/// Serialize `value` as a single newline-terminated JSON line.
+pub fn encode(value: &ModemUartMessages) -> Result<String<U1024>, ()> {
+    let mut line: String<U1024> = serde_json_core::to_string(value).map_err(|_| ())?;
+    line.push('\n').map_err(|_| ())?;
+    Ok(line)
+}
+
+/// Write `line` out over the UARTE with a blocking DMA transfer, used
+/// instead of the binary logger's own TX path since the two framings
+/// can't share the wire.
+pub fn write_line(uarte: &UARTE0, line: &str) {
+    let bytes = line.as_bytes();
+
+    uarte.txd.ptr.write(|w| unsafe { w.ptr().bits(bytes.as_ptr() as u32) });
+    uarte.txd.maxcnt.write(|w| unsafe { w.maxcnt().bits(bytes.len() as u16) });
+    uarte.events_endtx.reset();
+    uarte.tasks_starttx.write(|w| unsafe { w.bits(1) });
+
+    while uarte.events_endtx.read().bits() == 0 {}
+    uarte.events_endtx.reset();
+}
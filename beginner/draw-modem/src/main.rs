@@ -1,5 +1,6 @@
 #![no_main]
 #![no_std]
+#![feature(panic_info_message)]
 
 // Built in dependencies
 use core::fmt::Write;
@@ -12,12 +13,14 @@ use dwm1001::{
         delay::Delay,
         prelude::*,
         timer::Timer,
-        gpio::{Pin, Output, PushPull, Level, p0::P0_17},
+        gpio::{Pin, Output, PushPull, Level, Input, Floating, p0::P0_17},
         rng::Rng,
         spim::{Spim},
         nrf52832_pac::{
             TIMER0,
             SPIM2,
+            UARTE0,
+            NVMC,
         },
         uarte::Baudrate as UartBaudrate,
     },
@@ -29,17 +32,24 @@ use dwm1001::{
     dw1000::{
         macros::TimeoutError,
         mac::Address,
+        time::{Duration, Instant, SendTime},
         Message,
+        TxConfig,
     },
 };
 use heapless::{String, consts::*};
 use rtfm::app;
-use postcard::from_bytes;
-
-// NOTE: Panic Provider
-use panic_ramdump as _;
+use postcard::{from_bytes, to_slice};
+use nb::block;
 
 // Workspace dependencies
+//
+// NOTE: this series adds ModemUartMessages::Range and the
+// RadioMessages::{RangePoll, RangeResponse, RangeFinal, FwBegin, FwChunk,
+// FwCommit} variants it and the OTA path match on below. Those variants
+// belong in the `protocol` crate, which isn't part of this tree/diff --
+// the corresponding protocol-crate commit needs to land alongside this
+// one for the workspace to build.
 use protocol::{
     ModemUartMessages,
     CellCommand,
@@ -47,19 +57,41 @@ use protocol::{
 };
 use nrf52_bin_logger::Logger;
 
+#[cfg(feature = "json-log")]
+mod json_log;
+mod ota;
+mod panic_persist;
+mod ranging;
+mod uart_cmd;
+use ota::{OtaState, StagingFlash};
+use ranging::{RangingState, FinalTimestamps, distance_mm};
+use uart_cmd::UartCommandChannel;
+
+
+/// The DW1000 driver, configured with our SPI peripheral and chip-select
+/// pin, once ranging/communication config has been applied.
+type ModemDw1000 = DW<Spim<SPIM2>, P0_17<Output<PushPull>>, dw1000::Ready>;
+
+/// Result of successfully processing an incoming message: either a value
+/// to report to the host over UART, or confirmation it was handled with
+/// nothing to report, e.g. an intermediate DS-TWR stage or an OTA chunk
+/// ack. Distinct from `Err(())`, which means the message itself was bad.
+enum MessageOutcome {
+    Report(ModemUartMessages),
+    Handled,
+}
 
 #[app(device = dwm1001::nrf52832_hal::nrf52832_pac)]
 const APP: () = {
     static mut LED_RED_1: Pin<Output<PushPull>>     = ();
     static mut TIMER:     Timer<TIMER0>             = ();
     static mut LOGGER:    Logger<U1024, ModemUartMessages> = ();
-    static mut DW1000:    DW<
-                            Spim<SPIM2>,
-                            P0_17<Output<PushPull>>,
-                            dw1000::Ready,
-                          > = ();
+    static mut DW1000:    ModemDw1000                      = ();
     static mut DW_RST_PIN: DW_RST                   = ();
     static mut RANDOM:     Rng                      = ();
+    static mut CMD_CHANNEL: UartCommandChannel      = ();
+    static mut OTA_FLASH:   StagingFlash            = ();
+    static mut JSON_LOG_MODE: bool                  = ();
 
     #[init]
     fn init() {
@@ -101,20 +133,62 @@ const APP: () = {
             }
         ).unwrap();
 
+        // The RX half of the same physical UART link the logger writes
+        // out on; wired up separately since `new_usb_uarte` only hands us
+        // a TX-oriented writer.
+        let uarte0_regs = unsafe { &*UARTE0::ptr() };
+        let mut cmd_channel = UartCommandChannel::new(uarte0_regs, device.TIMER1, device.PPI);
+        cmd_channel.start_receive(uarte0_regs);
+
+        // Whether `data` goes out as JSON lines is a runtime strap, not
+        // just the `json-log` build flag: with the feature compiled in,
+        // a board can still be wired to read this pin low and keep
+        // binary framing, so the same firmware image serves both a
+        // human-readable JSON setup and a lower-overhead binary one.
+        #[cfg(feature = "json-log")]
+        let json_log_mode = pins.p0_22.into_floating_input().is_high();
+        #[cfg(not(feature = "json-log"))]
+        let json_log_mode = false;
+
         RANDOM = rng;
         DW_RST_PIN = rst_pin;
         DW1000 = dw1000;
         LOGGER = Logger::new(uarte0);
         TIMER = timer;
         LED_RED_1 = pins.p0_14.degrade().into_push_pull_output(Level::High);
+        CMD_CHANNEL = cmd_channel;
+        OTA_FLASH = StagingFlash::new(device.NVMC);
+        JSON_LOG_MODE = json_log_mode;
+
+        if let Some(panic_message) = panic_persist::take() {
+            LOGGER.error(panic_message.as_str()).unwrap();
+        }
+
+        if json_log_mode {
+            LOGGER.log("data output mode: JSON lines").unwrap();
+        }
+
+        ota::check_swap_state(&mut LOGGER);
     }
 
-    #[idle(resources = [TIMER, LED_RED_1, LOGGER, RANDOM, DW1000])]
+    #[idle(resources = [TIMER, LED_RED_1, LOGGER, RANDOM, DW1000, CMD_CHANNEL, OTA_FLASH, JSON_LOG_MODE])]
     fn idle() -> ! {
         let mut buffer = [0u8; 1024];
         let mut strbuf: String<U1024> = String::new();
+        let mut ranging_state = RangingState::default();
+        let mut ota_state = OtaState::default();
+        let uarte0_regs = unsafe { &*UARTE0::ptr() };
 
         loop {
+            if let Some(len) = resources.CMD_CHANNEL.take_frame_len(uarte0_regs) {
+                let frame_len = len.min(uart_cmd::CMD_BUFFER_LEN);
+                let mut cmd_buffer = [0u8; uart_cmd::CMD_BUFFER_LEN];
+                cmd_buffer[..frame_len].copy_from_slice(resources.CMD_CHANNEL.frame(frame_len));
+                resources.CMD_CHANNEL.start_receive(uarte0_regs);
+
+                handle_uart_command(resources.LOGGER, resources.DW1000, &cmd_buffer[..frame_len]);
+            }
+
             let mut rx = if let Ok(rx) = resources.DW1000.receive() {
                 rx
             } else {
@@ -126,13 +200,34 @@ const APP: () = {
             resources.TIMER.start(1_000_000u32);
 
             match block_timeout!(&mut *resources.TIMER, rx.wait(&mut buffer)) {
-                Ok(message) => {
-                    if let Ok(resp) = process_message(
-                        resources.LOGGER,
-                        &message
-                    ) {
-                        resources.LOGGER.data(resp).unwrap();
-                    } else {
+                Ok(message) => match process_message(
+                    resources.LOGGER,
+                    resources.DW1000,
+                    &mut ranging_state,
+                    resources.OTA_FLASH,
+                    &mut ota_state,
+                    &message
+                ) {
+                    Ok(MessageOutcome::Report(resp)) => {
+                        #[cfg(feature = "json-log")]
+                        let want_json = *resources.JSON_LOG_MODE;
+                        #[cfg(not(feature = "json-log"))]
+                        let want_json = false;
+
+                        if want_json {
+                            #[cfg(feature = "json-log")]
+                            match json_log::encode(&resp) {
+                                Ok(line) => json_log::write_line(uarte0_regs, line.as_str()),
+                                Err(_) => {
+                                    resources.LOGGER.error("failed to encode JSON log line").unwrap();
+                                }
+                            }
+                        } else {
+                            resources.LOGGER.data(resp).unwrap();
+                        }
+                    }
+                    Ok(MessageOutcome::Handled) => {}
+                    Err(()) => {
                         strbuf.clear();
                         write!(&mut strbuf, "^ Bad message from src 0x{:04X}", message.frame.header.source.short_addr).unwrap();
                         resources.LOGGER.warn(strbuf.as_str()).unwrap();
@@ -157,7 +252,19 @@ const MODEM_PAN: u16 = 0x0386;
 const MODEM_ADDR: u16 = 0x0808;
 const BROADCAST: u16 = 0xFFFF;
 
-fn process_message(logger: &mut Logger<U1024, ModemUartMessages>, msg: &Message) -> Result<ModemUartMessages, ()> {
+/// Fixed margin between receiving a `RangePoll` and the scheduled TX time
+/// of our `RangeResponse`, long enough to decode, encode and hand the
+/// reply off to the DW1000 before `t_sr` arrives.
+const RESPONSE_DELAY: Duration = Duration::from_nanos(1_000_000);
+
+fn process_message(
+    logger: &mut Logger<U1024, ModemUartMessages>,
+    dw1000: &mut ModemDw1000,
+    ranging_state: &mut RangingState,
+    ota_flash: &mut StagingFlash,
+    ota_state: &mut OtaState,
+    msg: &Message
+) -> Result<MessageOutcome, ()> {
     if msg.frame.header.source.pan_id == BROADCAST {
         logger.error("bad bdcst pan!").unwrap();
         return Err(())
@@ -178,14 +285,53 @@ fn process_message(logger: &mut Logger<U1024, ModemUartMessages>, msg: &Message)
         return Err(())
     }
 
+    let source = msg.frame.header.source.short_addr;
+
     if let Ok(pmsg) = from_bytes::<RadioMessages>(msg.frame.payload) {
         match pmsg {
             RadioMessages::SetCell(sc) => {
-                return Ok(ModemUartMessages::SetCell(CellCommand {
-                    source: msg.frame.header.source.short_addr,
+                return Ok(MessageOutcome::Report(ModemUartMessages::SetCell(CellCommand {
+                    source,
                     dest: msg.frame.header.destination.short_addr,
                     cell: sc
-                }));
+                })));
+            }
+            RadioMessages::RangePoll => {
+                return handle_range_poll(logger, dw1000, ranging_state, source, msg.rx_time);
+            }
+            RadioMessages::RangeResponse => {
+                logger.warn("unexpected RangeResponse").unwrap();
+            }
+            RadioMessages::RangeFinal { t_sp, t_rr, t_sf } => {
+                return handle_range_final(
+                    logger,
+                    ranging_state,
+                    source,
+                    msg.rx_time,
+                    FinalTimestamps { t_sp, t_rr, t_sf },
+                );
+            }
+            RadioMessages::FwBegin { total_len, crc } => {
+                if ota::begin(ota_flash, ota_state, total_len, crc).is_err() {
+                    logger.error("OTA: image too large for staging partition").unwrap();
+                    return Err(());
+                }
+                logger.log("OTA: FwBegin").unwrap();
+                return Ok(MessageOutcome::Handled);
+            }
+            RadioMessages::FwChunk { offset, bytes } => {
+                if ota::chunk(ota_flash, ota_state, offset, bytes).is_err() {
+                    logger.error("OTA: chunk write failed, aborting").unwrap();
+                } else {
+                    return Ok(MessageOutcome::Handled);
+                }
+            }
+            RadioMessages::FwCommit => {
+                // On success this never returns: ota::commit() resets the
+                // MCU to install the staged image.
+                if ota::commit(logger, ota_state).is_err() {
+                    logger.error("OTA: commit failed").unwrap();
+                }
             }
         }
     } else {
@@ -195,10 +341,126 @@ fn process_message(logger: &mut Logger<U1024, ModemUartMessages>, msg: &Message)
     Err(())
 }
 
-use nb::{
-    block,
-};
+/// Responder side of DS-TWR: a `RangePoll` arrived at `t_rp`, so schedule a
+/// `RangeResponse` transmission and remember our two timestamps until the
+/// matching `RangeFinal` shows up.
+fn handle_range_poll(
+    logger: &mut Logger<U1024, ModemUartMessages>,
+    dw1000: &mut ModemDw1000,
+    ranging_state: &mut RangingState,
+    initiator: u16,
+    t_rp: Instant,
+) -> Result<MessageOutcome, ()> {
+    // Give ourselves a fixed processing margin before the scheduled TX time,
+    // so the response timestamp is known ahead of the actual transmission.
+    let t_sr = t_rp + RESPONSE_DELAY;
 
+    let mut response_buf = [0u8; 32];
+    let payload = match to_slice(&RadioMessages::RangeResponse, &mut response_buf) {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            logger.error("failed to encode RangeResponse").unwrap();
+            return Err(());
+        }
+    };
+
+    let frame_addr = Address {
+        pan_id: MODEM_PAN,
+        short_addr: initiator,
+    };
+
+    match dw1000.send(payload, frame_addr, SendTime::Delayed(t_sr), TxConfig::default()) {
+        Ok(mut sending) => {
+            if block!(sending.wait()).is_err() {
+                logger.error("failed to send RangeResponse").unwrap();
+                return Err(());
+            }
+        }
+        Err(_) => {
+            logger.error("failed to start RangeResponse TX").unwrap();
+            return Err(());
+        }
+    }
+
+    *ranging_state = RangingState::AwaitingFinal {
+        initiator,
+        t_rp: t_rp.value(),
+        t_sr: t_sr.value(),
+    };
+
+    Ok(MessageOutcome::Handled)
+}
+
+/// Initiator side arrives with its three timestamps in `RangeFinal`; combine
+/// them with our own `t_rp`/`t_sr`/`t_rf` to compute distance.
+fn handle_range_final(
+    logger: &mut Logger<U1024, ModemUartMessages>,
+    ranging_state: &mut RangingState,
+    source: u16,
+    t_rf: Instant,
+    final_ts: FinalTimestamps,
+) -> Result<MessageOutcome, ()> {
+    let (initiator, t_rp, t_sr) = match *ranging_state {
+        RangingState::AwaitingFinal { initiator, t_rp, t_sr } => (initiator, t_rp, t_sr),
+        RangingState::AwaitingPoll => {
+            logger.warn("unexpected RangeFinal").unwrap();
+            return Err(());
+        }
+    };
+
+    *ranging_state = RangingState::AwaitingPoll;
+
+    if source != initiator {
+        logger.warn("RangeFinal from unexpected source").unwrap();
+        return Err(());
+    }
+
+    let millimeters = distance_mm(t_rp, t_sr, t_rf.value(), &final_ts);
+
+    Ok(MessageOutcome::Report(ModemUartMessages::Range {
+        addr: initiator,
+        millimeters,
+    }))
+}
+
+/// Transmit a host-issued UART command frame over UWB. The frame is a
+/// little-endian `u16` destination short address followed by a
+/// postcard-encoded `RadioMessages` payload; idle-line detection in
+/// `uart_cmd` already marked its boundary, so we just validate and send.
+fn handle_uart_command(
+    logger: &mut Logger<U1024, ModemUartMessages>,
+    dw1000: &mut ModemDw1000,
+    frame: &[u8],
+) {
+    if frame.len() < 2 {
+        logger.warn("UART cmd frame too short").unwrap();
+        return;
+    }
+
+    let (addr_bytes, payload) = frame.split_at(2);
+    let dest = u16::from_le_bytes([addr_bytes[0], addr_bytes[1]]);
+
+    if from_bytes::<RadioMessages>(payload).is_err() {
+        logger.warn("Failed to decode UART cmd").unwrap();
+        return;
+    }
+
+    let frame_addr = Address {
+        pan_id: MODEM_PAN,
+        short_addr: dest,
+    };
+
+    match dw1000.send(payload, frame_addr, SendTime::Now, TxConfig::default()) {
+        Ok(mut sending) => {
+            if block!(sending.wait()).is_err() {
+                logger.error("failed to send UART cmd").unwrap();
+            }
+        }
+        Err(_) => {
+            logger.error("failed to start UART cmd TX").unwrap();
+        }
+    }
+}
 
 pub fn delay<T>(timer: &mut Timer<T>, cycles: u32) where T: TimerExt {
     timer.start(cycles);
@@ -0,0 +1,308 @@
+//! Over-the-air firmware update.
+//!
+//! Firmware images arrive in pieces over the existing UWB receive loop as
+//! `RadioMessages::{FwBegin, FwChunk, FwCommit}`. Chunk bytes are written
+//! into a staging partition of the nRF52832's internal flash (the region
+//! between `_dfu_start` and `_dfu_end`, defined by the linker script)
+//! through a small `NorFlash` implementation over the NVMC peripheral,
+//! erasing each page the first time a chunk touches it and tracking a
+//! running CRC-32 as bytes come in. `FwCommit` checks that CRC against
+//! the one `FwBegin` promised, and if it matches, writes a swap marker
+//! for the bootloader and resets so the staged image gets installed.
+//!
+//! On the following boot, `check_swap_state` is called from `init()` to
+//! read back the updater state left by the bootloader (analogous to
+//! `get_state` on other DFU implementations): if we just booted a freshly
+//! swapped image, getting this far without panicking is our self-test,
+//! so we confirm it; a bootloader that instead finds no confirmation
+//! after a timeout is expected to roll back on its own.
+
+use core::convert::Infallible;
+use cortex_m::peripheral::SCB;
+use dwm1001::nrf52832_hal::nrf52832_pac::NVMC;
+use embedded_storage::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
+use nrf52_bin_logger::Logger;
+use protocol::ModemUartMessages;
+
+const PAGE_SIZE: u32 = 4096;
+
+// NOTE: `_dfu_start`/`_dfu_end`/`_dfu_state` are provided by the linker
+// script, which must reserve a page-aligned staging partition (and a
+// one-word state slot the bootloader also reads) outside the regions
+// cortex-m-rt hands to `.text`/`.data`/`.bss`. That memory.x change is a
+// companion commit of its own, like the protocol-crate additions noted
+// in main.rs, and isn't part of this tree.
+extern "C" {
+    static mut _dfu_start: u8;
+    static mut _dfu_end: u8;
+    static mut _dfu_state: u8;
+}
+
+const STATE_NONE: u32 = 0xFFFF_FFFF;
+const STATE_PENDING_TEST: u32 = 0x5453_4554; // "TEST"
+const STATE_CONFIRMED: u32 = 0x4E524643; // "CFRN"
+const STATE_SWAP_REQUESTED: u32 = 0x50415753; // "SWAP"
+
+pub enum UpdaterState {
+    None,
+    PendingTest,
+    Confirmed,
+}
+
+/// A `NorFlash`/`ReadNorFlash` implementation over the nRF52832's
+/// internal flash via the NVMC peripheral, scoped to the DFU staging
+/// partition reserved by the linker script.
+pub struct StagingFlash {
+    nvmc: NVMC,
+}
+
+impl StagingFlash {
+    pub fn new(nvmc: NVMC) -> Self {
+        StagingFlash { nvmc }
+    }
+
+    fn partition_base(&self) -> u32 {
+        unsafe { &_dfu_start as *const u8 as u32 }
+    }
+
+    fn partition_len(&self) -> u32 {
+        unsafe { (&_dfu_end as *const u8 as u32) - (&_dfu_start as *const u8 as u32) }
+    }
+
+    fn wait_ready(&self) {
+        while self.nvmc.ready.read().ready().is_busy() {}
+    }
+}
+
+impl ErrorType for StagingFlash {
+    type Error = Infallible;
+}
+
+impl ReadNorFlash for StagingFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let base = (self.partition_base() + offset) as *const u8;
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = unsafe { core::ptr::read(base.add(i)) };
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.partition_len() as usize
+    }
+}
+
+impl NorFlash for StagingFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = PAGE_SIZE as usize;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.nvmc.config.write(|w| w.wen().een());
+        self.wait_ready();
+
+        let mut page = from - (from % PAGE_SIZE);
+        while page < to {
+            let addr = self.partition_base() + page;
+            self.nvmc.erasepage.write(|w| unsafe { w.bits(addr) });
+            self.wait_ready();
+            page += PAGE_SIZE;
+        }
+
+        self.nvmc.config.write(|w| w.wen().ren());
+        self.wait_ready();
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.nvmc.config.write(|w| w.wen().wen());
+        self.wait_ready();
+
+        let base = (self.partition_base() + offset) as *mut u32;
+        for (i, word) in bytes.chunks(4).enumerate() {
+            let mut buf = [0xFFu8; 4];
+            buf[..word.len()].copy_from_slice(word);
+            let value = u32::from_le_bytes(buf);
+            unsafe { core::ptr::write_volatile(base.add(i), value) };
+            self.wait_ready();
+        }
+
+        self.nvmc.config.write(|w| w.wen().ren());
+        self.wait_ready();
+        Ok(())
+    }
+}
+
+/// Where we are in receiving a staged image.
+pub enum OtaState {
+    Idle,
+    Receiving {
+        total_len: u32,
+        crc_expected: u32,
+        written: u32,
+        crc_running: u32,
+        /// Offset up to which pages have already been erased, so a later
+        /// chunk landing in an already-erased page doesn't erase (and
+        /// wipe) bytes an earlier chunk just wrote into that same page.
+        erased_through: u32,
+    },
+}
+
+impl Default for OtaState {
+    fn default() -> Self {
+        OtaState::Idle
+    }
+}
+
+/// `FwBegin`: reset progress tracking for a new image of `total_len`
+/// bytes that should checksum to `crc`. Rejects (and leaves `ota_state`
+/// untouched) if `total_len` wouldn't fit in the staging partition,
+/// since accepting it would let later chunks erase/write past the
+/// partition into whatever flash sits next to it.
+pub fn begin(flash: &StagingFlash, ota_state: &mut OtaState, total_len: u32, crc: u32) -> Result<(), ()> {
+    if total_len as usize > flash.capacity() {
+        return Err(());
+    }
+
+    *ota_state = OtaState::Receiving {
+        total_len,
+        crc_expected: crc,
+        written: 0,
+        crc_running: 0xFFFF_FFFF,
+        erased_through: 0,
+    };
+    Ok(())
+}
+
+/// `FwChunk`: write `bytes` at `offset` into the staging partition,
+/// erasing every page the chunk newly touches, and fold the bytes into
+/// the running CRC. Chunks must arrive in order and be `WRITE_SIZE`
+/// aligned in both offset and length; out-of-order, out-of-range or
+/// misaligned chunks abort the transfer.
+pub fn chunk(
+    flash: &mut StagingFlash,
+    ota_state: &mut OtaState,
+    offset: u32,
+    bytes: &[u8],
+) -> Result<(), ()> {
+    let (total_len, crc_expected, written, crc_running, erased_through) = match *ota_state {
+        OtaState::Receiving { total_len, crc_expected, written, crc_running, erased_through } => {
+            (total_len, crc_expected, written, crc_running, erased_through)
+        }
+        OtaState::Idle => return Err(()),
+    };
+
+    let write_size = StagingFlash::WRITE_SIZE as u32;
+    if offset != written
+        || offset + bytes.len() as u32 > total_len
+        || offset % write_size != 0
+        || bytes.len() as u32 % write_size != 0
+    {
+        *ota_state = OtaState::Idle;
+        return Err(());
+    }
+
+    // Erase every page this chunk spans that hasn't already been erased
+    // for this transfer -- a chunk may start or end mid-page when it
+    // crosses a page boundary.
+    let last_byte = offset + bytes.len() as u32 - 1;
+    let mut page = offset - (offset % PAGE_SIZE);
+    let mut erased_through = erased_through;
+    while page <= last_byte {
+        if page >= erased_through {
+            flash.erase(page, page + PAGE_SIZE).map_err(|_| ())?;
+            erased_through = page + PAGE_SIZE;
+        }
+        page += PAGE_SIZE;
+    }
+
+    flash.write(offset, bytes).map_err(|_| ())?;
+
+    let crc_running = crc32(crc_running, bytes);
+
+    *ota_state = OtaState::Receiving {
+        total_len,
+        crc_expected,
+        written: written + bytes.len() as u32,
+        crc_running,
+        erased_through,
+    };
+
+    Ok(())
+}
+
+/// `FwCommit`: verify the staged image's CRC and, if it matches, mark it
+/// for the bootloader to swap in and reset. On mismatch, abort and leave
+/// the existing firmware running.
+pub fn commit(logger: &mut Logger<heapless::consts::U1024, ModemUartMessages>, ota_state: &mut OtaState) -> Result<(), ()> {
+    let (total_len, crc_expected, written, crc_running) = match *ota_state {
+        OtaState::Receiving { total_len, crc_expected, written, crc_running, .. } => {
+            (total_len, crc_expected, written, crc_running)
+        }
+        OtaState::Idle => return Err(()),
+    };
+
+    *ota_state = OtaState::Idle;
+
+    if written != total_len || !crc32_matches(crc_running, crc_expected) {
+        logger.error("OTA: CRC mismatch, not swapping").unwrap();
+        return Err(());
+    }
+
+    request_swap();
+    SCB::sys_reset();
+}
+
+fn crc32(running: u32, bytes: &[u8]) -> u32 {
+    let mut crc = running;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+fn crc32_matches(running: u32, expected: u32) -> bool {
+    !running == expected
+}
+
+fn state_word() -> *mut u32 {
+    unsafe { &mut _dfu_state as *mut u8 as *mut u32 }
+}
+
+fn request_swap() {
+    unsafe { core::ptr::write_volatile(state_word(), STATE_SWAP_REQUESTED) };
+}
+
+/// Read back the updater state the bootloader left behind, analogous to
+/// `get_state` on other DFU implementations.
+pub fn get_state() -> UpdaterState {
+    match unsafe { core::ptr::read_volatile(state_word()) } {
+        STATE_PENDING_TEST => UpdaterState::PendingTest,
+        STATE_CONFIRMED => UpdaterState::Confirmed,
+        STATE_NONE => UpdaterState::None,
+        _ => UpdaterState::None,
+    }
+}
+
+/// Mark the currently-running image as good so the bootloader won't roll
+/// it back.
+pub fn confirm() {
+    unsafe { core::ptr::write_volatile(state_word(), STATE_CONFIRMED) };
+}
+
+/// Called from `init()` on every boot: if we just came up from a freshly
+/// swapped image, getting this far without panicking is our self-test,
+/// so confirm it. Otherwise there's nothing to do.
+pub fn check_swap_state(logger: &mut Logger<heapless::consts::U1024, ModemUartMessages>) {
+    match get_state() {
+        UpdaterState::PendingTest => {
+            confirm();
+            logger.log("OTA: new image passed self-test, confirmed").unwrap();
+        }
+        UpdaterState::Confirmed | UpdaterState::None => {}
+    }
+}
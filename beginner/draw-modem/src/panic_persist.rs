@@ -0,0 +1,85 @@
+//! Persist panic messages across a soft reset.
+//!
+//! `PANIC_DUMP` lives in a `#[link_section]` that the linker script must
+//! place outside `.bss`/`.data` so it is never zeroed or reinitialized by
+//! the runtime startup code, letting its contents survive a reset. The
+//! panic handler formats the panic message and location into it along
+//! with a magic sentinel, then resets. On the next boot, `take()` checks
+//! the sentinel, hands back the stored message if one is present, and
+//! clears the sentinel so the same panic isn't reported twice.
+//!
+//! NOTE: like the DFU staging partition in ota.rs, `.uninit.PANIC_DUMP`
+//! needs a memory.x region reserved outside RAM's zero-initialized
+//! range, and a cortex-m-rt new enough that its startup code only zeros
+//! `.bss` proper rather than everything between `_sbss` and `_ebss`
+//! inclusive of custom sections. Neither the linker script change nor a
+//! cortex-m-rt version pin is part of this tree/diff; both need to land
+//! alongside this commit for the section placement here to actually
+//! survive a reset instead of getting zeroed at boot.
+
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use cortex_m::peripheral::SCB;
+use heapless::{consts::*, String};
+
+const MAGIC: u32 = 0xFEED_F00D;
+const MESSAGE_CAPACITY: usize = 256;
+
+#[repr(C)]
+struct PanicDump {
+    magic: u32,
+    len: u32,
+    message: [u8; MESSAGE_CAPACITY],
+}
+
+#[link_section = ".uninit.PANIC_DUMP"]
+static mut PANIC_DUMP: PanicDump = PanicDump {
+    magic: 0,
+    len: 0,
+    message: [0; MESSAGE_CAPACITY],
+};
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut buf: String<U256> = String::new();
+
+    if let Some(location) = info.location() {
+        let _ = write!(&mut buf, "panic at {}:{}: ", location.file(), location.line());
+    }
+
+    if let Some(args) = info.message() {
+        let _ = write!(&mut buf, "{}", args);
+    } else if let Some(msg) = info.payload().downcast_ref::<&str>() {
+        let _ = buf.push_str(msg);
+    }
+
+    unsafe {
+        let bytes = buf.as_bytes();
+        let len = bytes.len().min(MESSAGE_CAPACITY);
+        PANIC_DUMP.message[..len].copy_from_slice(&bytes[..len]);
+        PANIC_DUMP.len = len as u32;
+        PANIC_DUMP.magic = MAGIC;
+    }
+
+    SCB::sys_reset();
+}
+
+/// If a panic message was stashed across the last reset, return it and
+/// clear the sentinel so it isn't reported again on subsequent boots.
+pub fn take() -> Option<String<U256>> {
+    unsafe {
+        if PANIC_DUMP.magic != MAGIC {
+            return None;
+        }
+
+        let len = (PANIC_DUMP.len as usize).min(MESSAGE_CAPACITY);
+        let mut out: String<U256> = String::new();
+        if let Ok(s) = core::str::from_utf8(&PANIC_DUMP.message[..len]) {
+            let _ = out.push_str(s);
+        }
+
+        PANIC_DUMP.magic = 0;
+
+        Some(out)
+    }
+}
@@ -0,0 +1,75 @@
+//! Double-sided two-way ranging (DS-TWR) support for the modem.
+//!
+//! The modem plays the responder role: it answers a `RangePoll` with a
+//! `RangeResponse` sent at a scheduled TX timestamp, then waits for the
+//! initiator's `RangeFinal`, which carries the initiator's own three
+//! timestamps. Combining those with the three timestamps the modem
+//! observed locally (`t_rp`, `t_sr`, `t_rf`) gives both sides an agreed
+//! time-of-flight without requiring synchronized clocks.
+
+/// The DW1000 timestamp counter is 40 bits wide, so every timestamp
+/// difference has to be taken modulo this to handle wraparound.
+const TIMESTAMP_MODULUS: i64 = 1 << 40;
+
+/// Duration of one DW1000 time unit, in femtoseconds (~15.65 ps).
+const DW1000_TICK_FS: u64 = 15_650;
+
+/// Speed of light, in millimeters per second.
+const SPEED_OF_LIGHT_MM_PER_S: u64 = 299_792_458_000;
+
+/// Where the responder is within a single DS-TWR exchange.
+pub enum RangingState {
+    /// Waiting for a `RangePoll` from an initiator.
+    AwaitingPoll,
+    /// Sent a `RangeResponse` to `initiator` at `t_sr` after receiving its
+    /// poll at `t_rp`; now waiting for the matching `RangeFinal`.
+    AwaitingFinal {
+        initiator: u16,
+        t_rp: u64,
+        t_sr: u64,
+    },
+}
+
+impl Default for RangingState {
+    fn default() -> Self {
+        RangingState::AwaitingPoll
+    }
+}
+
+/// Timestamps carried by the initiator's `RangeFinal` message.
+pub struct FinalTimestamps {
+    pub t_sp: u64,
+    pub t_rr: u64,
+    pub t_sf: u64,
+}
+
+/// `a - b`, taken modulo the 40-bit DW1000 timestamp counter.
+fn wrapping_diff(a: u64, b: u64) -> i64 {
+    (((a as i64 - b as i64) % TIMESTAMP_MODULUS) + TIMESTAMP_MODULUS) % TIMESTAMP_MODULUS
+}
+
+/// Compute the DS-TWR distance in millimeters from the responder's own
+/// `t_rp`/`t_sr`/`t_rf` timestamps and the initiator's `RangeFinal`
+/// timestamps.
+pub fn distance_mm(t_rp: u64, t_sr: u64, t_rf: u64, final_ts: &FinalTimestamps) -> u32 {
+    // round1/reply2 are differences of two initiator timestamps; reply1/
+    // round2 are differences of two responder timestamps. Keeping each
+    // difference within a single clock domain is what cancels clock
+    // offset between the two sides -- mixing domains (e.g. t_rf - t_sf)
+    // would leave the offset in the result instead of just the time of
+    // flight.
+    let round1 = wrapping_diff(final_ts.t_rr, final_ts.t_sp);
+    let reply1 = wrapping_diff(t_sr, t_rp);
+    let round2 = wrapping_diff(t_rf, t_sr);
+    let reply2 = wrapping_diff(final_ts.t_sf, final_ts.t_rr);
+
+    // round1/round2/reply1/reply2 are each < 2^40, so their products can
+    // reach ~2^80 -- wide enough to overflow i64 on a single mis-ordered
+    // or wraparound-adjacent timestamp. Do the multiply/subtract in i128.
+    let numerator = round1 as i128 * round2 as i128 - reply1 as i128 * reply2 as i128;
+    let denominator = (round1 + round2 + reply1 + reply2) as i128;
+    let tof_ticks = numerator / denominator;
+    let tof_fs = tof_ticks as u64 * DW1000_TICK_FS;
+
+    ((tof_fs as u128 * SPEED_OF_LIGHT_MM_PER_S as u128) / 1_000_000_000_000_000) as u32
+}
@@ -0,0 +1,97 @@
+//! Host -> modem command channel.
+//!
+//! Host tooling sends frames over the same UART the modem already uses
+//! for logging: a little-endian `u16` destination address followed by a
+//! postcard-encoded `RadioMessages` payload. Frames carry no length
+//! prefix and are variable length, so instead of reading a fixed number of bytes we
+//! arm the UARTE's RX DMA and rely on idle-line detection to know when a
+//! frame has ended: a spare `TIMER1` is wired through two PPI channels so
+//! that every received byte (`UARTE::EVENTS_RXDRDY`) restarts the timer,
+//! and if the timer ever reaches its idle threshold (~2 byte-times of
+//! silence) it stops the DMA transfer (`UARTE::TASKS_STOPRX`). The
+//! resulting `EVENTS_ENDRX` marks a complete frame sitting in the buffer,
+//! with no CPU involvement while bytes are still arriving.
+
+use dwm1001::nrf52832_hal::nrf52832_pac::{PPI, TIMER1, UARTE0};
+
+pub const CMD_BUFFER_LEN: usize = 256;
+
+/// ~2 byte-times of silence at 230400 baud (8N1, ~43us/byte) with
+/// TIMER1 running off the 16MHz HFCLK prescaled by 2^4.
+const IDLE_TIMEOUT_TICKS: u32 = 87;
+
+pub struct UartCommandChannel {
+    buffer: [u8; CMD_BUFFER_LEN],
+}
+
+impl UartCommandChannel {
+    /// Wire TIMER1 and two PPI channels for idle-line detection and arm
+    /// the first receive. `uarte`'s RX pin must already be configured
+    /// (it is, via `new_usb_uarte`'s pin setup).
+    pub fn new(uarte: &UARTE0, timer: TIMER1, ppi: PPI) -> Self {
+        timer.mode.write(|w| w.mode().timer());
+        timer.bitmode.write(|w| w.bitmode()._16bit());
+        timer.prescaler.write(|w| unsafe { w.prescaler().bits(4) });
+        timer.cc[0].write(|w| unsafe { w.cc().bits(IDLE_TIMEOUT_TICKS) });
+        // Only clear on compare, never stop: the timer has to keep running
+        // (and re-arming via channel 0 on every byte) so idle-line
+        // detection fires again for every later frame, not just the first.
+        timer.shorts.write(|w| w.compare0_clear().set_bit());
+
+        // Channel 0: every received byte restarts the idle timer.
+        ppi.ch[0]
+            .eep
+            .write(|w| unsafe { w.bits(&uarte.events_rxdrdy as *const _ as u32) });
+        ppi.ch[0]
+            .tep
+            .write(|w| unsafe { w.bits(&timer.tasks_clear as *const _ as u32) });
+
+        // Channel 1: idle timeout stops the DMA receive, ending the frame.
+        ppi.ch[1]
+            .eep
+            .write(|w| unsafe { w.bits(&timer.events_compare[0] as *const _ as u32) });
+        ppi.ch[1]
+            .tep
+            .write(|w| unsafe { w.bits(&uarte.tasks_stoprx as *const _ as u32) });
+
+        ppi.chenset.write(|w| w.ch0().set_bit().ch1().set_bit());
+
+        timer.tasks_start.write(|w| unsafe { w.bits(1) });
+
+        UartCommandChannel {
+            buffer: [0; CMD_BUFFER_LEN],
+        }
+    }
+
+    /// Arm a new DMA receive into our buffer. Must be called once after
+    /// `new()` and again after every completed frame.
+    pub fn start_receive(&mut self, uarte: &UARTE0) {
+        uarte
+            .rxd
+            .ptr
+            .write(|w| unsafe { w.ptr().bits(self.buffer.as_mut_ptr() as u32) });
+        uarte
+            .rxd
+            .maxcnt
+            .write(|w| unsafe { w.maxcnt().bits(CMD_BUFFER_LEN as u16) });
+        uarte.events_endrx.reset();
+        uarte.tasks_startrx.write(|w| unsafe { w.bits(1) });
+    }
+
+    /// If a frame has completed (`EVENTS_ENDRX` fired), return its length
+    /// and clear the event. Callers must re-arm with `start_receive` once
+    /// they're done reading `frame()`.
+    pub fn take_frame_len(&mut self, uarte: &UARTE0) -> Option<usize> {
+        if uarte.events_endrx.read().bits() == 0 {
+            return None;
+        }
+
+        uarte.events_endrx.reset();
+        Some(uarte.rxd.amount.read().amount().bits() as usize)
+    }
+
+    /// The `len` bytes of the most recently completed frame.
+    pub fn frame(&self, len: usize) -> &[u8] {
+        &self.buffer[..len]
+    }
+}